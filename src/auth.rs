@@ -0,0 +1,93 @@
+use std::sync::Arc;
+
+use pyo3::prelude::*;
+
+/// The access a connection has been granted to a room.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    Denied,
+    Read,
+    ReadWrite,
+}
+
+impl Permission {
+    pub fn can_read(self) -> bool {
+        matches!(self, Permission::Read | Permission::ReadWrite)
+    }
+
+    pub fn can_write(self) -> bool {
+        matches!(self, Permission::ReadWrite)
+    }
+}
+
+/// Resolves what access a connection has to a room. Implementations are
+/// consulted once per connection and the result is cached for the room's
+/// lifetime (see `invalidate_permissions`).
+pub trait PermissionResolver: Send + Sync {
+    fn resolve(&self, room: &str, conn_id: u64) -> Permission;
+}
+
+/// A `PermissionResolver` that delegates to a Python callable invoked as
+/// `callback(room, conn_id) -> (can_read: bool, can_write: bool)`. Any
+/// error raised by the callable, or an unexpected return value, is
+/// treated as a denial rather than propagated.
+pub struct PyPermissionResolver {
+    callback: PyObject,
+}
+
+impl PyPermissionResolver {
+    pub fn new(callback: PyObject) -> Self {
+        PyPermissionResolver { callback }
+    }
+}
+
+impl PermissionResolver for PyPermissionResolver {
+    fn resolve(&self, room: &str, conn_id: u64) -> Permission {
+        Python::with_gil(|py| match self.callback.call1(py, (room, conn_id)) {
+            Ok(result) => match result.extract::<(bool, bool)>(py) {
+                Ok((can_read, can_write)) => match (can_read, can_write) {
+                    (true, true) => Permission::ReadWrite,
+                    (true, false) => Permission::Read,
+                    (false, false) => Permission::Denied,
+                    (false, true) => {
+                        log::error!(
+                            "Permission resolver granted write without read for room '{}' connection {}; denying",
+                            room,
+                            conn_id
+                        );
+                        Permission::Denied
+                    }
+                },
+                Err(e) => {
+                    log::error!(
+                        "Permission resolver returned bad data for room '{}' connection {}: {}",
+                        room,
+                        conn_id,
+                        e
+                    );
+                    Permission::Denied
+                }
+            },
+            Err(e) => {
+                log::error!(
+                    "Permission resolver failed for room '{}' connection {}: {}",
+                    room,
+                    conn_id,
+                    e
+                );
+                Permission::Denied
+            }
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct PermissionConfig {
+    pub resolver: Arc<dyn PermissionResolver>,
+}
+
+impl std::fmt::Debug for PermissionConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PermissionConfig").finish()
+    }
+}
@@ -1,6 +1,9 @@
 use pyo3::prelude::*;
 
+mod auth;
+mod crypto;
 mod roomsync;
+mod storage;
 
 /// A Python module implemented in Rust.
 #[pymodule]
@@ -1,6 +1,12 @@
 use std::{
     collections::{HashMap, HashSet},
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, RwLock},
+};
+
+use crate::auth::{Permission, PermissionConfig, PyPermissionResolver};
+use crate::crypto::EncryptionConfig;
+use crate::storage::{
+    self, CompactionCounters, FilesystemStorage, PyCallbackStorage, RoomStorageConfig,
 };
 
 use pyo3::{
@@ -147,11 +153,18 @@ impl From<u8> for ProtocolVersion {
     }
 }
 
+const DEFAULT_COMPACTION_ENTRIES: usize = 1000;
+const DEFAULT_COMPACTION_BYTES: usize = 5 * 1024 * 1024;
+
 #[derive(Clone, Debug)]
 struct YRoomSettings {
     pub protocol_version: ProtocolVersion,
     pub name_prefix: bool,
     pub server_start_sync: bool,
+    pub storage: Option<RoomStorageConfig>,
+    pub permission: Option<PermissionConfig>,
+    pub encryption: Option<EncryptionConfig>,
+    pub custom_message_types: HashSet<u8>,
 }
 
 impl Default for YRoomSettings {
@@ -160,6 +173,10 @@ impl Default for YRoomSettings {
             protocol_version: ProtocolVersion::V1,
             name_prefix: false,
             server_start_sync: true,
+            storage: None,
+            permission: None,
+            encryption: None,
+            custom_message_types: HashSet::new(),
         }
     }
 }
@@ -180,15 +197,82 @@ impl FromPyObject<'_> for YRoomSettings {
             Some(server_start_sync) => server_start_sync.extract::<bool>()?,
             None => true,
         };
+        let storage = Self::extract_storage(settings)?;
+        let permission = Self::extract_permission(settings)?;
+        let encryption = Self::extract_encryption(settings)?;
+        let custom_message_types = Self::extract_custom_message_types(settings)?;
 
         Ok(YRoomSettings {
             protocol_version,
             name_prefix,
             server_start_sync,
+            storage,
+            permission,
+            encryption,
+            custom_message_types,
         })
     }
 }
 
+impl YRoomSettings {
+    fn extract_storage(settings: &PyDict) -> PyResult<Option<RoomStorageConfig>> {
+        let entry_threshold = match settings.get_item("STORAGE_COMPACTION_ENTRIES") {
+            Some(value) => value.extract::<usize>()?,
+            None => DEFAULT_COMPACTION_ENTRIES,
+        };
+        let byte_threshold = match settings.get_item("STORAGE_COMPACTION_BYTES") {
+            Some(value) => value.extract::<usize>()?,
+            None => DEFAULT_COMPACTION_BYTES,
+        };
+
+        if let Some(path) = settings.get_item("STORAGE_PATH") {
+            let path: String = path.extract()?;
+            return Ok(Some(RoomStorageConfig {
+                storage: Arc::new(FilesystemStorage::new(path)),
+                compaction_entry_threshold: entry_threshold,
+                compaction_byte_threshold: byte_threshold,
+            }));
+        }
+        if let Some(callback) = settings.get_item("STORAGE") {
+            return Ok(Some(RoomStorageConfig {
+                storage: Arc::new(PyCallbackStorage::new(callback.into())),
+                compaction_entry_threshold: entry_threshold,
+                compaction_byte_threshold: byte_threshold,
+            }));
+        }
+        Ok(None)
+    }
+
+    fn extract_permission(settings: &PyDict) -> PyResult<Option<PermissionConfig>> {
+        match settings.get_item("PERMISSION_RESOLVER") {
+            Some(callback) => Ok(Some(PermissionConfig {
+                resolver: Arc::new(PyPermissionResolver::new(callback.into())),
+            })),
+            None => Ok(None),
+        }
+    }
+
+    fn extract_encryption(settings: &PyDict) -> PyResult<Option<EncryptionConfig>> {
+        match settings.get_item("ENCRYPTION_KEY") {
+            Some(key) => {
+                let key = key.extract::<Vec<u8>>()?;
+                Ok(EncryptionConfig::new(&key))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// The set of `Message::Custom` type tags that are relayed to other
+    /// connections in the room. Unlisted tags are dropped, same as before
+    /// this setting existed.
+    fn extract_custom_message_types(settings: &PyDict) -> PyResult<HashSet<u8>> {
+        match settings.get_item("CUSTOM_MESSAGE_TYPES") {
+            Some(types) => Ok(types.extract::<Vec<u8>>()?.into_iter().collect()),
+            None => Ok(HashSet::new()),
+        }
+    }
+}
+
 #[pyclass]
 pub struct YRoomMessage {
     #[pyo3(get)]
@@ -213,7 +297,7 @@ impl YRoomMessage {
 
 #[pyclass]
 pub struct YRoomManager {
-    rooms: HashMap<String, YRoom>,
+    rooms: RwLock<HashMap<String, Arc<Mutex<YRoom>>>>,
     default_settings: YRoomSettings,
     room_settings: Vec<(String, YRoomSettings)>,
 }
@@ -234,36 +318,88 @@ impl YRoomManager {
         }
 
         YRoomManager {
-            rooms: HashMap::new(),
+            rooms: RwLock::new(HashMap::new()),
             default_settings,
             room_settings,
         }
     }
     fn new_with_default() -> Self {
         YRoomManager {
-            rooms: HashMap::new(),
+            rooms: RwLock::new(HashMap::new()),
             default_settings: YRoomSettings::default(),
             room_settings: Vec::default(),
         }
     }
-    fn get_room_with_data(&mut self, room: &str, data: Vec<u8>) -> &mut YRoom {
+
+    /// Looks up a room, creating it (seeded from `data` if given) on
+    /// first access. Uses double-checked locking: an existing room is
+    /// found under a cheap read lock, and only a genuinely new room
+    /// needs the write lock, which is re-checked in case another thread
+    /// raced to create it first.
+    fn get_room_arc(&self, room: &str, data: Option<Vec<u8>>) -> Arc<Mutex<YRoom>> {
+        if let Some(yroom) = self.rooms.read().unwrap().get(room) {
+            return yroom.clone();
+        }
+
         let settings = self.find_settings(room);
-        self.rooms.entry(room.to_string()).or_insert_with(|| {
-            log::info!(
-                "Creating new YRoom '{}' with data and settings {:?}",
-                room,
-                settings
-            );
-            YRoom::new(settings, Some(data))
-        })
+        let (updates, counters) = Self::resolve_initial_updates(&settings, room, data);
+        let mut rooms = self.rooms.write().unwrap();
+        if let Some(yroom) = rooms.get(room) {
+            return yroom.clone();
+        }
+        log::info!("Creating new YRoom '{}' with settings {:?}", room, settings);
+        let yroom = Arc::new(Mutex::new(YRoom::new(
+            room.to_string(),
+            settings,
+            updates,
+            counters,
+        )));
+        rooms.insert(room.to_string(), yroom.clone());
+        yroom
     }
 
-    fn get_room(&mut self, room: &str) -> &mut YRoom {
-        let settings = self.find_settings(room);
-        self.rooms.entry(room.to_string()).or_insert_with(|| {
-            log::info!("Creating new YRoom '{}' with settings {:?}", room, settings);
-            YRoom::new(settings, None)
-        })
+    /// Determines the updates a freshly created room should be seeded
+    /// with: persisted storage takes priority over an explicitly passed
+    /// `data` blob, which is only used to bootstrap a room storage has
+    /// never seen before. Whichever source is used is transparently
+    /// decrypted when `ENCRYPTION_KEY` is configured, matching the
+    /// wrapping `persist_update`/`compact`/`serialize` apply on the way
+    /// out. Also returns `CompactionCounters` seeded from the size of the
+    /// log segment that was just replayed, so a room reloaded with a
+    /// near-threshold log doesn't have to accumulate a second full
+    /// threshold of updates before it compacts again.
+    fn resolve_initial_updates(
+        settings: &YRoomSettings,
+        room: &str,
+        data: Option<Vec<u8>>,
+    ) -> (Vec<Vec<u8>>, CompactionCounters) {
+        if let Some(config) = &settings.storage {
+            let persisted = storage::load_room_updates(config.storage.as_ref(), room);
+            if !persisted.is_empty() {
+                let log = config.storage.load_log(room);
+                let mut counters = CompactionCounters::default();
+                for entry in &log {
+                    counters.record(entry.len());
+                }
+                let updates = match &settings.encryption {
+                    Some(encryption) => persisted
+                        .iter()
+                        .map(|entry| encryption.decrypt_or_legacy(entry))
+                        .collect(),
+                    None => persisted,
+                };
+                return (updates, counters);
+            }
+        }
+        let data = match (&settings.encryption, data) {
+            (Some(encryption), Some(data)) => Some(encryption.decrypt_or_legacy(&data)),
+            (_, data) => data,
+        };
+        let updates = match data {
+            Some(data) => vec![data],
+            None => Vec::new(),
+        };
+        (updates, CompactionCounters::default())
     }
 
     fn find_settings(&self, room: &str) -> YRoomSettings {
@@ -278,6 +414,12 @@ impl YRoomManager {
 
 const DEFAULT_KEY: &str = "default";
 
+/// The `origin_conn_id` passed to update callbacks for updates that came
+/// in via server-to-server peer replication rather than a real client
+/// connection. Chosen from the far end of the id space so it can't
+/// collide with an actual `conn_id`.
+const PEER_ORIGIN_CONN_ID: u64 = u64::MAX;
+
 #[pymethods]
 impl YRoomManager {
     #[new]
@@ -288,163 +430,590 @@ impl YRoomManager {
         }
     }
 
-    pub fn connect(&mut self, room: String, conn_id: u64) -> YRoomMessage {
-        self.get_room(&room).connect(conn_id)
+    pub fn connect(&self, py: Python<'_>, room: String, conn_id: u64) -> YRoomMessage {
+        let yroom = self.get_room_arc(&room, None);
+        py.allow_threads(|| yroom.lock().unwrap().connect(conn_id))
     }
-    pub fn connect_with_data(&mut self, room: String, conn_id: u64, data: Vec<u8>) -> YRoomMessage {
-        self.get_room_with_data(&room, data).connect(conn_id)
+    pub fn connect_with_data(
+        &self,
+        py: Python<'_>,
+        room: String,
+        conn_id: u64,
+        data: Vec<u8>,
+    ) -> YRoomMessage {
+        let yroom = self.get_room_arc(&room, Some(data));
+        py.allow_threads(|| yroom.lock().unwrap().connect(conn_id))
     }
 
-    pub fn handle_message(&mut self, room: String, conn_id: u64, payload: Vec<u8>) -> YRoomMessage {
-        self.get_room(&room).handle_message(conn_id, payload)
+    /// Locks only the target room for the duration of the CRDT work and
+    /// releases the GIL around it, so Python threads processing other
+    /// rooms aren't blocked.
+    pub fn handle_message(
+        &self,
+        py: Python<'_>,
+        room: String,
+        conn_id: u64,
+        payload: Vec<u8>,
+    ) -> YRoomMessage {
+        let yroom = self.get_room_arc(&room, None);
+        py.allow_threads(|| yroom.lock().unwrap().handle_message(conn_id, payload))
     }
 
-    pub fn disconnect(&mut self, room: String, conn_id: u64) -> YRoomMessage {
-        let broadcast_payload = self.get_room(&room).disconnect(conn_id);
-        Python::with_gil(|py| YRoomMessage {
+    pub fn disconnect(&self, py: Python<'_>, room: String, conn_id: u64) -> YRoomMessage {
+        let yroom = self.get_room_arc(&room, None);
+        let broadcast_payload = py.allow_threads(|| yroom.lock().unwrap().disconnect(conn_id));
+        YRoomMessage {
             payload: PyBytes::new(py, &[]).into(),
             broadcast_payload: PyBytes::new(py, &broadcast_payload).into(),
-        })
+        }
     }
 
     pub fn has_room(&self, room: String) -> bool {
-        self.rooms.contains_key(&room)
+        self.rooms.read().unwrap().contains_key(&room)
     }
 
-    pub fn is_room_alive(&self, room: String) -> bool {
-        let room = self.rooms.get(&room);
-        match room {
-            Some(room) => room.is_alive(),
+    pub fn is_room_alive(&self, py: Python<'_>, room: String) -> bool {
+        match self.rooms.read().unwrap().get(&room).cloned() {
+            Some(yroom) => py.allow_threads(|| yroom.lock().unwrap().is_alive()),
             None => false,
         }
     }
 
-    pub fn serialize_room(&self, room: String) -> PyObject {
-        let yroom = self.rooms.get(&room);
-        Python::with_gil(|py| match yroom {
+    pub fn serialize_room(&self, py: Python<'_>, room: String) -> PyObject {
+        let yroom = self.rooms.read().unwrap().get(&room).cloned();
+        let serialized = match yroom {
+            None => return py.None(),
+            Some(yroom) => py.allow_threads(|| yroom.lock().unwrap().serialize()),
+        };
+        match serialized {
+            Some(data) => PyBytes::new(py, &data).into(),
             None => py.None(),
-            Some(yroom) => PyBytes::new(py, &yroom.serialize()).into(),
-        })
+        }
+    }
+
+    /// Drops a room from memory. When `drop_persisted` is set, also
+    /// deletes its snapshot and update log from the configured storage.
+    pub fn remove_room(&self, room: String, drop_persisted: bool) {
+        self.rooms.write().unwrap().remove(&room);
+        if drop_persisted {
+            if let Some(config) = self.find_settings(&room).storage {
+                config.storage.remove_room(&room);
+            }
+        }
+    }
+
+    /// Forces immediate compaction of a room's pending update log into a
+    /// new snapshot, regardless of whether the configured thresholds have
+    /// been reached.
+    pub fn flush_room(&self, py: Python<'_>, room: String) {
+        if let Some(yroom) = self.rooms.read().unwrap().get(&room).cloned() {
+            py.allow_threads(|| yroom.lock().unwrap().flush());
+        }
+    }
+
+    /// Eagerly creates a room from its configured storage, without
+    /// waiting for a client to connect. A room already in memory is left
+    /// as it is.
+    pub fn load_room(&self, room: String) {
+        self.get_room_arc(&room, None);
+    }
+
+    /// Registers a Python callable to be invoked as
+    /// `callback(room, update_bytes, origin_conn_id)` whenever an update
+    /// is applied to the room's document, even one that changes nothing
+    /// (see `notify_update_callbacks`). Returns a callback id
+    /// that can later be passed to `unregister_update_callback`.
+    pub fn register_update_callback(
+        &self,
+        py: Python<'_>,
+        room: String,
+        callback: PyObject,
+    ) -> u64 {
+        let yroom = self.get_room_arc(&room, None);
+        py.allow_threads(|| yroom.lock().unwrap().register_update_callback(callback))
     }
 
-    pub fn remove_room(&mut self, room: String) {
-        self.rooms.remove(&room);
+    pub fn unregister_update_callback(&self, py: Python<'_>, room: String, callback_id: u64) {
+        if let Some(yroom) = self.rooms.read().unwrap().get(&room).cloned() {
+            py.allow_threads(|| {
+                yroom
+                    .lock()
+                    .unwrap()
+                    .unregister_update_callback(callback_id)
+            });
+        }
+    }
+
+    /// Registers a Python callable to be invoked as
+    /// `callback(room, added, updated, removed)` (client id lists)
+    /// whenever an awareness message from a client connection changes
+    /// the room's awareness state. States removed on `disconnect` and
+    /// awareness updates applied via `apply_peer_message` do not invoke it.
+    pub fn register_awareness_callback(
+        &self,
+        py: Python<'_>,
+        room: String,
+        callback: PyObject,
+    ) -> u64 {
+        let yroom = self.get_room_arc(&room, None);
+        py.allow_threads(|| yroom.lock().unwrap().register_awareness_callback(callback))
+    }
+
+    pub fn unregister_awareness_callback(&self, py: Python<'_>, room: String, callback_id: u64) {
+        if let Some(yroom) = self.rooms.read().unwrap().get(&room).cloned() {
+            py.allow_threads(|| {
+                yroom
+                    .lock()
+                    .unwrap()
+                    .unregister_awareness_callback(callback_id)
+            });
+        }
+    }
+
+    /// Forces the configured `PERMISSION_RESOLVER` to be consulted again
+    /// for this connection on its next message, e.g. after a role change.
+    pub fn invalidate_permissions(&self, py: Python<'_>, room: String, conn_id: u64) {
+        if let Some(yroom) = self.rooms.read().unwrap().get(&room).cloned() {
+            py.allow_threads(|| yroom.lock().unwrap().invalidate_permissions(conn_id));
+        }
+    }
+
+    /// Builds a `SyncStep1` (plus current awareness) payload for shipping
+    /// to a remote peer, so it can compute the diff this room is missing.
+    pub fn begin_peer_sync(&self, py: Python<'_>, room: String) -> PyObject {
+        let yroom = self.get_room_arc(&room, None);
+        let payload = py.allow_threads(|| yroom.lock().unwrap().begin_peer_sync());
+        PyBytes::new(py, &payload).into()
+    }
+
+    /// Applies a `SyncStep1`/`SyncStep2`/`Update`/`Awareness` message
+    /// received from a remote peer. `payload` is the diff the peer still
+    /// needs; `broadcast_payload` is what this room's own local clients
+    /// should be sent to catch up.
+    pub fn apply_peer_message(
+        &self,
+        py: Python<'_>,
+        room: String,
+        payload: Vec<u8>,
+    ) -> YRoomMessage {
+        let yroom = self.get_room_arc(&room, None);
+        py.allow_threads(|| yroom.lock().unwrap().apply_peer_message(payload))
     }
 
     pub fn list_rooms(&self) -> Vec<String> {
-        self.rooms.keys().cloned().collect()
+        self.rooms.read().unwrap().keys().cloned().collect()
     }
 
-    pub fn export_map(&self, room: String, name: String) -> PyObject {
-        let yroom = self.rooms.get(&room);
+    pub fn export_map(&self, py: Python<'_>, room: String, name: String) -> PyObject {
+        let yroom = self.rooms.read().unwrap().get(&room).cloned();
         match yroom {
-            Some(room) => {
-                let doc = room.awareness.doc();
-                let obj = doc.get_or_insert_map(&name);
-                let serialized = obj.to_json(&doc.transact());
+            Some(yroom) => {
+                let serialized = py.allow_threads(|| {
+                    let yroom = yroom.lock().unwrap();
+                    let doc = yroom.awareness.doc();
+                    let obj = doc.get_or_insert_map(&name);
+                    obj.to_json(&doc.transact())
+                });
                 let mut result = Default::default();
                 serialized.to_json(&mut result);
-                Python::with_gil(|py| result.to_object(py))
+                result.to_object(py)
             }
-            None => Python::with_gil(|py| py.None()),
+            None => py.None(),
         }
     }
 
-    pub fn export_array(&self, room: String, name: String) -> PyObject {
-        let yroom = self.rooms.get(&room);
+    pub fn export_array(&self, py: Python<'_>, room: String, name: String) -> PyObject {
+        let yroom = self.rooms.read().unwrap().get(&room).cloned();
         match yroom {
-            Some(room) => {
-                let doc = room.awareness.doc();
-                let obj = doc.get_or_insert_array(&name);
-                let serialized = obj.to_json(&doc.transact());
+            Some(yroom) => {
+                let serialized = py.allow_threads(|| {
+                    let yroom = yroom.lock().unwrap();
+                    let doc = yroom.awareness.doc();
+                    let obj = doc.get_or_insert_array(&name);
+                    obj.to_json(&doc.transact())
+                });
                 let mut result = Default::default();
                 serialized.to_json(&mut result);
-                Python::with_gil(|py| result.to_object(py))
+                result.to_object(py)
             }
-            None => Python::with_gil(|py| py.None()),
+            None => py.None(),
         }
     }
 
-    pub fn export_text(&self, room: String, name: String) -> PyObject {
-        let yroom = self.rooms.get(&room);
+    pub fn export_text(&self, py: Python<'_>, room: String, name: String) -> PyObject {
+        let yroom = self.rooms.read().unwrap().get(&room).cloned();
         match yroom {
-            Some(room) => {
-                let doc = room.awareness.doc();
-                let obj = doc.get_or_insert_text(&name);
-
-                let serialized = obj.get_string(&doc.transact());
-                Python::with_gil(|py| serialized.to_object(py))
+            Some(yroom) => {
+                let serialized = py.allow_threads(|| {
+                    let yroom = yroom.lock().unwrap();
+                    let doc = yroom.awareness.doc();
+                    let obj = doc.get_or_insert_text(&name);
+                    obj.get_string(&doc.transact())
+                });
+                serialized.to_object(py)
             }
-            None => Python::with_gil(|py| py.None()),
+            None => py.None(),
         }
     }
 
-    pub fn export_xml_element(&self, room: String, name: String) -> PyObject {
-        let yroom = self.rooms.get(&room);
+    pub fn export_xml_element(&self, py: Python<'_>, room: String, name: String) -> PyObject {
+        let yroom = self.rooms.read().unwrap().get(&room).cloned();
         match yroom {
-            Some(room) => {
-                let doc = room.awareness.doc();
-                let obj = doc.get_or_insert_xml_element(&name);
-
-                let serialized = obj.get_string(&doc.transact());
-                Python::with_gil(|py| serialized.to_object(py))
+            Some(yroom) => {
+                let serialized = py.allow_threads(|| {
+                    let yroom = yroom.lock().unwrap();
+                    let doc = yroom.awareness.doc();
+                    let obj = doc.get_or_insert_xml_element(&name);
+                    obj.get_string(&doc.transact())
+                });
+                serialized.to_object(py)
             }
-            None => Python::with_gil(|py| py.None()),
+            None => py.None(),
         }
     }
 
-    pub fn export_xml_text(&self, room: String, name: String) -> PyObject {
-        let yroom = self.rooms.get(&room);
+    pub fn export_xml_text(&self, py: Python<'_>, room: String, name: String) -> PyObject {
+        let yroom = self.rooms.read().unwrap().get(&room).cloned();
         match yroom {
-            Some(room) => {
-                let doc = room.awareness.doc();
-                let obj = doc.get_or_insert_xml_text(&name);
-
-                let serialized = obj.get_string(&doc.transact());
-                Python::with_gil(|py| serialized.to_object(py))
+            Some(yroom) => {
+                let serialized = py.allow_threads(|| {
+                    let yroom = yroom.lock().unwrap();
+                    let doc = yroom.awareness.doc();
+                    let obj = doc.get_or_insert_xml_text(&name);
+                    obj.get_string(&doc.transact())
+                });
+                serialized.to_object(py)
             }
-            None => Python::with_gil(|py| py.None()),
+            None => py.None(),
         }
     }
 
-    pub fn export_xml_fragment(&self, room: String, name: String) -> PyObject {
-        let yroom = self.rooms.get(&room);
+    pub fn export_xml_fragment(&self, py: Python<'_>, room: String, name: String) -> PyObject {
+        let yroom = self.rooms.read().unwrap().get(&room).cloned();
         match yroom {
-            Some(room) => {
-                let doc = room.awareness.doc();
-                let obj = doc.get_or_insert_xml_fragment(&name);
-
-                let serialized = obj.get_string(&doc.transact());
-                Python::with_gil(|py| serialized.to_object(py))
+            Some(yroom) => {
+                let serialized = py.allow_threads(|| {
+                    let yroom = yroom.lock().unwrap();
+                    let doc = yroom.awareness.doc();
+                    let obj = doc.get_or_insert_xml_fragment(&name);
+                    obj.get_string(&doc.transact())
+                });
+                serialized.to_object(py)
             }
-            None => Python::with_gil(|py| py.None()),
+            None => py.None(),
         }
     }
 }
 
 pub struct YRoom {
+    name: String,
     awareness: Awareness,
     connections: Arc<Mutex<HashMap<u64, HashSet<u64>>>>,
     settings: YRoomSettings,
+    storage: Option<RoomStorageConfig>,
+    counters: CompactionCounters,
+    update_callbacks: HashMap<u64, PyObject>,
+    awareness_callbacks: HashMap<u64, PyObject>,
+    next_callback_id: u64,
+    permission_cache: HashMap<u64, Permission>,
 }
 
 impl YRoom {
-    fn new(settings: YRoomSettings, update_vec: Option<Vec<u8>>) -> Self {
+    fn new(
+        name: String,
+        settings: YRoomSettings,
+        updates: Vec<Vec<u8>>,
+        counters: CompactionCounters,
+    ) -> Self {
         let mut awareness = Awareness::default();
-        if let Some(update_vec) = update_vec {
-            let update = Update::decode_v1(&update_vec);
+        for update_vec in updates {
+            let update = match settings.protocol_version {
+                ProtocolVersion::V1 => Update::decode_v1(&update_vec),
+                ProtocolVersion::V2 => Update::decode_v2(&update_vec),
+            };
             match update {
                 Ok(update) => {
                     let mut txn = awareness.doc_mut().transact_mut();
                     txn.apply_update(update);
                 }
-                Err(e) => log::error!("Error decoding update: {}", e),
+                Err(e) => log::error!("Error decoding update for room '{}': {}", name, e),
             }
         }
+        let storage = settings.storage.clone();
         YRoom {
+            name,
             awareness,
             connections: Arc::new(Mutex::new(HashMap::new())),
             settings,
+            storage,
+            counters,
+            update_callbacks: HashMap::new(),
+            awareness_callbacks: HashMap::new(),
+            next_callback_id: 0,
+            permission_cache: HashMap::new(),
+        }
+    }
+
+    /// Resolves (and caches) the permission a connection has in this
+    /// room. With no `PERMISSION_RESOLVER` configured, every connection
+    /// is granted read-write access, matching the room's historical
+    /// auto-grant behavior.
+    fn resolve_permission(&mut self, conn_id: u64) -> Permission {
+        if let Some(permission) = self.permission_cache.get(&conn_id) {
+            return *permission;
+        }
+        let permission = match &self.settings.permission {
+            Some(config) => config.resolver.resolve(&self.name, conn_id),
+            None => Permission::ReadWrite,
+        };
+        self.permission_cache.insert(conn_id, permission);
+        permission
+    }
+
+    fn invalidate_permissions(&mut self, conn_id: u64) {
+        self.permission_cache.remove(&conn_id);
+    }
+
+    fn register_update_callback(&mut self, callback: PyObject) -> u64 {
+        let id = self.next_callback_id;
+        self.next_callback_id += 1;
+        self.update_callbacks.insert(id, callback);
+        id
+    }
+
+    fn unregister_update_callback(&mut self, callback_id: u64) {
+        self.update_callbacks.remove(&callback_id);
+    }
+
+    fn register_awareness_callback(&mut self, callback: PyObject) -> u64 {
+        let id = self.next_callback_id;
+        self.next_callback_id += 1;
+        self.awareness_callbacks.insert(id, callback);
+        id
+    }
+
+    fn unregister_awareness_callback(&mut self, callback_id: u64) {
+        self.awareness_callbacks.remove(&callback_id);
+    }
+
+    /// Invokes every registered update callback with the raw inbound
+    /// update bytes and the connection that originated them. "Raw" is
+    /// deliberate: this fires for every successfully decoded and applied
+    /// update message, not a filtered diff of what actually changed in
+    /// the document, so a client resending a no-op update still notifies
+    /// subscribers. Called only after the mutable transaction that
+    /// applied the update has been dropped, so a callback that merely
+    /// reads from the document doesn't race a dangling transaction. This
+    /// does *not* make the room reentrant: the caller's `Mutex<YRoom>` is
+    /// still held while this runs, so a callback that calls back into
+    /// `YRoomManager` for this same room (e.g. `handle_message`) will
+    /// deadlock on that lock, not just risk a transaction-level issue.
+    fn notify_update_callbacks(&self, data: &[u8], origin_conn_id: u64) {
+        if self.update_callbacks.is_empty() {
+            return;
+        }
+        Python::with_gil(|py| {
+            let update_bytes = PyBytes::new(py, data);
+            for callback in self.update_callbacks.values() {
+                if let Err(e) =
+                    callback.call1(py, (self.name.clone(), update_bytes, origin_conn_id))
+                {
+                    log::error!("Update callback error for room '{}': {}", self.name, e);
+                }
+            }
+        });
+    }
+
+    /// Encrypts `data` for storage when `ENCRYPTION_KEY` is configured, so
+    /// the on-disk log/snapshot format matches what `serialize` hands out.
+    /// Returns `None` (after logging) if encryption is configured but
+    /// fails, so callers can skip the write instead of persisting a
+    /// plaintext blob the key was meant to protect.
+    fn encrypt_for_storage(&self, data: &[u8]) -> Option<Vec<u8>> {
+        match &self.settings.encryption {
+            Some(encryption) => encryption.encrypt(data),
+            None => Some(data.to_vec()),
+        }
+    }
+
+    /// Appends `data` to the room's update log (if storage is configured)
+    /// and triggers compaction once the configured thresholds are hit.
+    fn persist_update(&mut self, data: &[u8]) {
+        let config = match &self.storage {
+            Some(config) => config.clone(),
+            None => return,
+        };
+        let stored = match self.encrypt_for_storage(data) {
+            Some(stored) => stored,
+            None => {
+                log::error!("Skipping persistence of update for room '{}'", self.name);
+                return;
+            }
+        };
+        config.storage.append_update(&self.name, &stored);
+        self.counters.record(stored.len());
+        if self.counters.exceeds(
+            config.compaction_entry_threshold,
+            config.compaction_byte_threshold,
+        ) {
+            self.compact();
+        }
+    }
+
+    /// Writes the current document state as a new snapshot, then
+    /// truncates the log. The snapshot is always written before the log
+    /// is truncated, so a crash in between leaves the room recoverable.
+    fn compact(&mut self) {
+        let config = match &self.storage {
+            Some(config) => config.clone(),
+            None => return,
+        };
+        let txn = self.awareness.doc().transact();
+        let snapshot = match self.settings.protocol_version {
+            ProtocolVersion::V1 => txn.encode_state_as_update_v1(&StateVector::default()),
+            ProtocolVersion::V2 => txn.encode_state_as_update_v2(&StateVector::default()),
+        };
+        drop(txn);
+        let stored = match self.encrypt_for_storage(&snapshot) {
+            Some(stored) => stored,
+            None => {
+                log::error!("Skipping compaction for room '{}'", self.name);
+                return;
+            }
+        };
+        config.storage.save_snapshot(&self.name, &stored);
+        config.storage.truncate_log(&self.name);
+        self.counters.reset();
+    }
+
+    /// Forces compaction now, regardless of the pending log size.
+    fn flush(&mut self) {
+        if self.storage.is_some() {
+            self.compact();
+        }
+    }
+
+    fn encoder_prefix(&self) -> Option<String> {
+        if self.settings.name_prefix {
+            Some(self.name.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Builds a `SyncStep1` (plus current awareness, if any) payload for
+    /// a remote peer to diff against.
+    fn begin_peer_sync(&self) -> Vec<u8> {
+        let mut encoder =
+            EncoderWrapper::new(&self.settings.protocol_version, self.encoder_prefix());
+        let sv = self.awareness.doc().transact().state_vector();
+        encoder.push(Message::Sync(SyncMessage::SyncStep1(sv)));
+        if !self.awareness.clients().is_empty() {
+            if let Ok(awareness_update) = self.awareness.update() {
+                encoder.push(Message::Awareness(awareness_update));
+            }
+        }
+        encoder.to_vec()
+    }
+
+    /// Applies a message received from a remote peer (no permission
+    /// checks apply; replication is a trusted, server-to-server channel).
+    /// Updates are persisted and dispatched to update callbacks with
+    /// `PEER_ORIGIN_CONN_ID` as their origin, so subscribers can tell
+    /// replicated changes apart from ones made by a local client.
+    fn apply_peer_message(&mut self, payload: Vec<u8>) -> YRoomMessage {
+        let cursor = Cursor::new(&payload);
+        let decoder = match DecoderWrapper::new(
+            &self.settings.protocol_version,
+            cursor,
+            self.settings.name_prefix,
+        ) {
+            Ok(decoder) => decoder,
+            Err(e) => {
+                log::error!(
+                    "Error decoding peer message for room '{}': {}",
+                    self.name,
+                    e
+                );
+                return Python::with_gil(|py| YRoomMessage {
+                    payload: PyBytes::new(py, &[]).into(),
+                    broadcast_payload: PyBytes::new(py, &[]).into(),
+                });
+            }
+        };
+
+        let prefix = self.encoder_prefix();
+        let mut sync_encoder = EncoderWrapper::new(&self.settings.protocol_version, prefix.clone());
+        let mut update_encoder = EncoderWrapper::new(&self.settings.protocol_version, prefix);
+
+        decoder.for_each(|message_result| match message_result {
+            Ok(message) => match message {
+                Message::Sync(SyncMessage::SyncStep1(sv)) => {
+                    let txn = self.awareness.doc_mut().transact_mut();
+                    let data = match self.settings.protocol_version {
+                        ProtocolVersion::V1 => txn.encode_diff_v1(&sv),
+                        ProtocolVersion::V2 => {
+                            let mut enc = EncoderV2::new();
+                            txn.encode_diff(&sv, &mut enc);
+                            enc.to_vec()
+                        }
+                    };
+                    sync_encoder.push(Message::Sync(SyncMessage::SyncStep2(data)));
+                }
+                Message::Sync(SyncMessage::SyncStep2(data)) => {
+                    self.apply_peer_update(data, &mut update_encoder);
+                }
+                Message::Sync(SyncMessage::Update(data)) => {
+                    self.apply_peer_update(data, &mut update_encoder);
+                }
+                Message::AwarenessQuery => {
+                    if let Ok(awareness_update) = self.awareness.update() {
+                        sync_encoder.push(Message::Awareness(awareness_update));
+                    }
+                }
+                Message::Awareness(awareness_update) => {
+                    if let Err(e) = self.awareness.apply_update(awareness_update) {
+                        log::error!(
+                            "Error applying peer awareness update for room '{}': {}",
+                            self.name,
+                            e
+                        );
+                    }
+                    if let Ok(awareness_update) = self.awareness.update() {
+                        update_encoder.push(Message::Awareness(awareness_update));
+                    }
+                }
+                Message::Auth(_) | Message::Custom(_, _) => {
+                    log::debug!("Ignoring unsupported peer message for room '{}'", self.name);
+                }
+            },
+            Err(err) => {
+                log::warn!("Bad peer message for room '{}': {:?}", self.name, err);
+            }
+        });
+
+        Python::with_gil(|py| YRoomMessage {
+            payload: PyBytes::new(py, &sync_encoder.to_vec()).into(),
+            broadcast_payload: PyBytes::new(py, &update_encoder.to_vec()).into(),
+        })
+    }
+
+    fn apply_peer_update(&mut self, data: Vec<u8>, update_encoder: &mut EncoderWrapper) {
+        let update = match self.settings.protocol_version {
+            ProtocolVersion::V1 => Update::decode_v1(&data),
+            ProtocolVersion::V2 => Update::decode_v2(&data),
+        };
+        match update {
+            Ok(update) => {
+                {
+                    let mut txn = self.awareness.doc_mut().transact_mut();
+                    txn.apply_update(update);
+                }
+                self.persist_update(&data);
+                self.notify_update_callbacks(&data, PEER_ORIGIN_CONN_ID);
+                update_encoder.push(Message::Sync(SyncMessage::Update(data)));
+            }
+            Err(e) => log::error!("Error decoding peer update for room '{}': {}", self.name, e),
         }
     }
 
@@ -458,12 +1027,21 @@ impl YRoom {
         let mut encoder = EncoderWrapper::new(&self.settings.protocol_version, None);
 
         if self.settings.server_start_sync {
-            let sv = self.awareness.doc().transact().state_vector();
-            encoder.push(Message::Sync(SyncMessage::SyncStep1(sv)));
-            if !self.awareness.clients().is_empty() {
-                if let Ok(awareness_update) = self.awareness.update() {
-                    encoder.push(Message::Awareness(awareness_update));
+            let permission = self.resolve_permission(conn_id);
+            if permission.can_read() {
+                let sv = self.awareness.doc().transact().state_vector();
+                encoder.push(Message::Sync(SyncMessage::SyncStep1(sv)));
+                if !self.awareness.clients().is_empty() {
+                    if let Ok(awareness_update) = self.awareness.update() {
+                        encoder.push(Message::Awareness(awareness_update));
+                    }
                 }
+            } else {
+                log::debug!(
+                    "Connection {} denied read access to room '{}'; suppressing initial sync",
+                    conn_id,
+                    self.name
+                );
             }
         }
         let payload = encoder.to_vec();
@@ -501,9 +1079,19 @@ impl YRoom {
             decoder.document_name.clone(),
         );
 
+        let permission = self.resolve_permission(conn_id);
+
         decoder.for_each(|message_result| match message_result {
             Ok(message) => match message {
                 Message::Sync(SyncMessage::SyncStep1(sv)) => {
+                    if !permission.can_read() {
+                        log::debug!(
+                            "Connection {} denied read access to room '{}'; suppressing sync reply",
+                            conn_id,
+                            self.name
+                        );
+                        return;
+                    }
                     let txn = self.awareness.doc_mut().transact_mut();
                     let data = match self.settings.protocol_version {
                         ProtocolVersion::V1 => txn.encode_diff_v1(&sv),
@@ -518,24 +1106,42 @@ impl YRoom {
                     sync_encoder.push(message);
                 }
                 Message::Sync(SyncMessage::SyncStep2(data)) => {
+                    if !permission.can_write() {
+                        sync_encoder
+                            .push(Message::Auth(Some("permission denied: write".to_string())));
+                        return;
+                    }
                     let update = match self.settings.protocol_version {
                         ProtocolVersion::V1 => Update::decode_v1(&data),
                         ProtocolVersion::V2 => Update::decode_v2(&data),
                     };
                     match update {
                         Ok(update) => {
-                            let mut txn = self.awareness.doc_mut().transact_mut();
-                            txn.apply_update(update);
+                            {
+                                let mut txn = self.awareness.doc_mut().transact_mut();
+                                txn.apply_update(update);
+                            }
+                            self.persist_update(&data);
+                            self.notify_update_callbacks(&data, conn_id);
                         }
                         Err(e) => log::error!("Error decoding sync step 2: {}", e),
                     }
                 }
                 Message::Sync(SyncMessage::Update(data)) => {
+                    if !permission.can_write() {
+                        sync_encoder
+                            .push(Message::Auth(Some("permission denied: write".to_string())));
+                        return;
+                    }
                     let update = Update::decode_v1(&data);
                     match update {
                         Ok(update) => {
-                            let mut txn = self.awareness.doc_mut().transact_mut();
-                            txn.apply_update(update);
+                            {
+                                let mut txn = self.awareness.doc_mut().transact_mut();
+                                txn.apply_update(update);
+                            }
+                            self.persist_update(&data);
+                            self.notify_update_callbacks(&data, conn_id);
                             let message = Message::Sync(SyncMessage::Update(data));
                             update_encoder.push(message)
                         }
@@ -543,16 +1149,26 @@ impl YRoom {
                     }
                 }
                 Message::Auth(_) => {
-                    // TODO: check this. Always reply with permission granted
-                    log::warn!("Auth message received. Replying with permission granted");
-                    sync_encoder.push(Message::Auth(None))
+                    if permission.can_read() {
+                        sync_encoder.push(Message::Auth(None));
+                    } else {
+                        sync_encoder
+                            .push(Message::Auth(Some("permission denied: read".to_string())));
+                    }
                 }
                 Message::AwarenessQuery => {
-                    if let Ok(awareness_update) = self.awareness.update() {
-                        sync_encoder.push(Message::Awareness(awareness_update))
+                    if permission.can_read() {
+                        if let Ok(awareness_update) = self.awareness.update() {
+                            sync_encoder.push(Message::Awareness(awareness_update))
+                        }
                     }
                 }
                 Message::Awareness(awareness_update) => {
+                    if !permission.can_write() {
+                        sync_encoder
+                            .push(Message::Auth(Some("permission denied: write".to_string())));
+                        return;
+                    }
                     // Add/remove client ids to conn ids
                     self.connections
                         .lock()
@@ -560,7 +1176,15 @@ impl YRoom {
                         .entry(conn_id)
                         .or_insert_with(HashSet::new);
                     let connections = self.connections.clone();
+                    // Collected from the `on_update` subscription below,
+                    // then used to invoke awareness callbacks only once
+                    // `apply_update` (and the subscription itself) have
+                    // finished, not from inside the subscription. The
+                    // room's `Mutex` is still held, so a callback that
+                    // calls back into this room will deadlock.
+                    let changes = Arc::new(Mutex::new((Vec::new(), Vec::new(), Vec::new())));
                     {
+                        let changes = changes.clone();
                         let _sub = self.awareness.on_update(move |_, ev| {
                             let mut connections = connections.lock().unwrap();
                             let client_ids = connections.get_mut(&conn_id).unwrap();
@@ -570,18 +1194,57 @@ impl YRoom {
                             ev.removed().iter().for_each(|client_id| {
                                 client_ids.remove(client_id);
                             });
+                            drop(connections);
+                            let mut changes = changes.lock().unwrap();
+                            changes.0.extend_from_slice(ev.added());
+                            changes.1.extend_from_slice(ev.updated());
+                            changes.2.extend_from_slice(ev.removed());
                         });
                         if let Err(e) = self.awareness.apply_update(awareness_update) {
                             log::error!("Error applying awareness update: {}", e);
                         }
                     }
+                    if !self.awareness_callbacks.is_empty() {
+                        let (added, updated, removed) = Arc::try_unwrap(changes)
+                            .map(|m| m.into_inner().unwrap())
+                            .unwrap_or_default();
+                        if !added.is_empty() || !updated.is_empty() || !removed.is_empty() {
+                            let room_name = self.name.clone();
+                            Python::with_gil(|py| {
+                                for callback in self.awareness_callbacks.values() {
+                                    if let Err(e) = callback.call1(
+                                        py,
+                                        (
+                                            room_name.clone(),
+                                            added.clone(),
+                                            updated.clone(),
+                                            removed.clone(),
+                                        ),
+                                    ) {
+                                        log::error!(
+                                            "Awareness callback error for room '{}': {}",
+                                            room_name,
+                                            e
+                                        );
+                                    }
+                                }
+                            });
+                        }
+                    }
                     if let Ok(awareness_update) = self.awareness.update() {
                         update_encoder.push(Message::Awareness(awareness_update))
                     }
                 }
-                Message::Custom(custom_type, _) => {
-                    // FIXME: handle custom
-                    log::warn!("Unhandled custom message received. Type: {}", custom_type);
+                Message::Custom(custom_type, data) => {
+                    if self.settings.custom_message_types.contains(&custom_type) {
+                        update_encoder.push(Message::Custom(custom_type, data));
+                    } else {
+                        log::debug!(
+                            "Dropping custom message of disallowed type {} for room '{}'",
+                            custom_type,
+                            self.name
+                        );
+                    }
                 }
             },
             Err(err) => {
@@ -614,11 +1277,20 @@ impl YRoom {
         encoder.to_vec()
     }
 
-    pub fn serialize(&self) -> Vec<u8> {
+    /// Serializes the room's full document state, encrypting it if
+    /// `ENCRYPTION_KEY` is configured. Returns `None` (after logging, via
+    /// `encrypt`) if encryption is configured but fails, rather than
+    /// silently handing out an empty blob that would read back as an
+    /// empty document.
+    pub fn serialize(&self) -> Option<Vec<u8>> {
         let txn = self.awareness.doc().transact();
-        match self.settings.protocol_version {
+        let data = match self.settings.protocol_version {
             ProtocolVersion::V1 => txn.encode_state_as_update_v1(&StateVector::default()),
             ProtocolVersion::V2 => txn.encode_state_as_update_v2(&StateVector::default()),
+        };
+        match &self.settings.encryption {
+            Some(encryption) => encryption.encrypt(&data),
+            None => Some(data),
         }
     }
 
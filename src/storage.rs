@@ -0,0 +1,298 @@
+use std::{fs, path::PathBuf, sync::Mutex};
+
+use lib0::{
+    decoding::{Cursor, Read},
+    encoding::Write,
+};
+use pyo3::{prelude::*, types::PyBytes};
+
+/// Persistence backend for room state: an append-only update log plus
+/// periodic snapshots, so a room's full history doesn't need to be held
+/// (or re-written) in memory on every save.
+///
+/// Implementations must be safe to call from multiple rooms concurrently;
+/// they are keyed by room name rather than holding per-room handles.
+pub trait Storage: Send + Sync {
+    /// Append an already-encoded update to the room's log.
+    fn append_update(&self, room: &str, data: &[u8]);
+    /// Load all log entries appended since the last snapshot, in order.
+    fn load_log(&self, room: &str) -> Vec<Vec<u8>>;
+    /// Replace the room's snapshot. Must be called (and durable) before
+    /// `truncate_log`, so a crash between the two never loses data.
+    fn save_snapshot(&self, room: &str, data: &[u8]);
+    /// Load the most recent snapshot, if any.
+    fn load_snapshot(&self, room: &str) -> Option<Vec<u8>>;
+    /// Drop all log entries, typically right after a successful
+    /// `save_snapshot`.
+    fn truncate_log(&self, room: &str);
+    /// Drop all persisted data (snapshot and log) for a room.
+    fn remove_room(&self, room: &str);
+}
+
+/// A `Storage` backed by a directory on disk: `<room>.snapshot` holds the
+/// latest snapshot, `<room>.log` holds length-prefixed update entries
+/// appended in order.
+pub struct FilesystemStorage {
+    base_dir: PathBuf,
+    // Serializes access to the storage files. Each method holds it only
+    // for its own read or write, so a compaction's `save_snapshot` and
+    // `truncate_log` are two separately locked steps.
+    lock: Mutex<()>,
+}
+
+impl FilesystemStorage {
+    pub fn new(base_dir: impl Into<PathBuf>) -> Self {
+        let base_dir = base_dir.into();
+        if let Err(e) = fs::create_dir_all(&base_dir) {
+            log::error!("Error creating storage directory {:?}: {}", base_dir, e);
+        }
+        FilesystemStorage {
+            base_dir,
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn snapshot_path(&self, room: &str) -> PathBuf {
+        self.base_dir.join(format!("{}.snapshot", sanitize(room)))
+    }
+
+    fn log_path(&self, room: &str) -> PathBuf {
+        self.base_dir.join(format!("{}.log", sanitize(room)))
+    }
+}
+
+fn sanitize(room: &str) -> String {
+    room.replace(
+        |c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+        "_",
+    )
+}
+
+impl Storage for FilesystemStorage {
+    fn append_update(&self, room: &str, data: &[u8]) {
+        let _guard = self.lock.lock().unwrap();
+        let mut encoded = Vec::new();
+        encoded.write_buf(data);
+        if let Err(e) = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.log_path(room))
+            .and_then(|mut f| {
+                use std::io::Write as _;
+                f.write_all(&encoded)
+            })
+        {
+            log::error!("Error appending update for room '{}': {}", room, e);
+        }
+    }
+
+    fn load_log(&self, room: &str) -> Vec<Vec<u8>> {
+        let _guard = self.lock.lock().unwrap();
+        let bytes = match fs::read(self.log_path(room)) {
+            Ok(bytes) => bytes,
+            Err(_) => return Vec::new(),
+        };
+        let mut entries = Vec::new();
+        let mut cursor = Cursor::new(&bytes);
+        while cursor.has_content() {
+            match cursor.read_buf() {
+                Ok(entry) => entries.push(entry.to_vec()),
+                Err(e) => {
+                    log::error!("Skipping corrupt log entry for room '{}': {}", room, e);
+                    break;
+                }
+            }
+        }
+        entries
+    }
+
+    fn save_snapshot(&self, room: &str, data: &[u8]) {
+        let _guard = self.lock.lock().unwrap();
+        if let Err(e) = fs::write(self.snapshot_path(room), data) {
+            log::error!("Error saving snapshot for room '{}': {}", room, e);
+        }
+    }
+
+    fn load_snapshot(&self, room: &str) -> Option<Vec<u8>> {
+        let _guard = self.lock.lock().unwrap();
+        fs::read(self.snapshot_path(room)).ok()
+    }
+
+    fn truncate_log(&self, room: &str) {
+        let _guard = self.lock.lock().unwrap();
+        if let Err(e) = fs::remove_file(self.log_path(room)) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                log::error!("Error truncating log for room '{}': {}", room, e);
+            }
+        }
+    }
+
+    fn remove_room(&self, room: &str) {
+        let _guard = self.lock.lock().unwrap();
+        let _ = fs::remove_file(self.snapshot_path(room));
+        let _ = fs::remove_file(self.log_path(room));
+    }
+}
+
+/// A `Storage` that delegates to a Python object implementing the same
+/// methods (`append_update`, `load_log`, `save_snapshot`, `load_snapshot`,
+/// `truncate_log`, `remove_room`), so server authors can back rooms with
+/// whatever database they already use.
+pub struct PyCallbackStorage {
+    callback: PyObject,
+}
+
+impl PyCallbackStorage {
+    pub fn new(callback: PyObject) -> Self {
+        PyCallbackStorage { callback }
+    }
+}
+
+impl Storage for PyCallbackStorage {
+    fn append_update(&self, room: &str, data: &[u8]) {
+        Python::with_gil(|py| {
+            if let Err(e) =
+                self.callback
+                    .call_method1(py, "append_update", (room, PyBytes::new(py, data)))
+            {
+                log::error!(
+                    "Storage callback append_update failed for '{}': {}",
+                    room,
+                    e
+                );
+            }
+        });
+    }
+
+    fn load_log(&self, room: &str) -> Vec<Vec<u8>> {
+        Python::with_gil(
+            |py| match self.callback.call_method1(py, "load_log", (room,)) {
+                Ok(result) => match result.extract::<Vec<Vec<u8>>>(py) {
+                    Ok(entries) => entries,
+                    Err(e) => {
+                        log::error!(
+                            "Storage callback load_log returned bad data for '{}': {}",
+                            room,
+                            e
+                        );
+                        Vec::new()
+                    }
+                },
+                Err(e) => {
+                    log::error!("Storage callback load_log failed for '{}': {}", room, e);
+                    Vec::new()
+                }
+            },
+        )
+    }
+
+    fn save_snapshot(&self, room: &str, data: &[u8]) {
+        Python::with_gil(|py| {
+            if let Err(e) =
+                self.callback
+                    .call_method1(py, "save_snapshot", (room, PyBytes::new(py, data)))
+            {
+                log::error!(
+                    "Storage callback save_snapshot failed for '{}': {}",
+                    room,
+                    e
+                );
+            }
+        });
+    }
+
+    fn load_snapshot(&self, room: &str) -> Option<Vec<u8>> {
+        Python::with_gil(
+            |py| match self.callback.call_method1(py, "load_snapshot", (room,)) {
+                Ok(result) => result.extract::<Option<Vec<u8>>>(py).unwrap_or_else(|e| {
+                    log::error!(
+                        "Storage callback load_snapshot returned bad data for '{}': {}",
+                        room,
+                        e
+                    );
+                    None
+                }),
+                Err(e) => {
+                    log::error!(
+                        "Storage callback load_snapshot failed for '{}': {}",
+                        room,
+                        e
+                    );
+                    None
+                }
+            },
+        )
+    }
+
+    fn truncate_log(&self, room: &str) {
+        Python::with_gil(|py| {
+            if let Err(e) = self.callback.call_method1(py, "truncate_log", (room,)) {
+                log::error!("Storage callback truncate_log failed for '{}': {}", room, e);
+            }
+        });
+    }
+
+    fn remove_room(&self, room: &str) {
+        Python::with_gil(|py| {
+            if let Err(e) = self.callback.call_method1(py, "remove_room", (room,)) {
+                log::error!("Storage callback remove_room failed for '{}': {}", room, e);
+            }
+        });
+    }
+}
+
+/// Reconstructs a room's encoded state by loading the latest snapshot (if
+/// any) followed by every log entry recorded since. Entries are returned
+/// undecoded; `YRoom::new` skips any that fail to decode rather than
+/// aborting the whole load.
+pub fn load_room_updates(storage: &dyn Storage, room: &str) -> Vec<Vec<u8>> {
+    let mut updates = Vec::new();
+    if let Some(snapshot) = storage.load_snapshot(room) {
+        updates.push(snapshot);
+    }
+    updates.extend(storage.load_log(room));
+    updates
+}
+
+/// Tracks how much uncompacted log data a room has accumulated, so the
+/// caller can decide when to trigger compaction.
+#[derive(Default)]
+pub struct CompactionCounters {
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+impl CompactionCounters {
+    pub fn record(&mut self, entry_len: usize) {
+        self.entries += 1;
+        self.bytes += entry_len;
+    }
+
+    pub fn reset(&mut self) {
+        self.entries = 0;
+        self.bytes = 0;
+    }
+
+    pub fn exceeds(&self, entry_threshold: usize, byte_threshold: usize) -> bool {
+        self.entries >= entry_threshold || self.bytes >= byte_threshold
+    }
+}
+
+#[derive(Clone)]
+pub struct RoomStorageConfig {
+    pub storage: std::sync::Arc<dyn Storage>,
+    pub compaction_entry_threshold: usize,
+    pub compaction_byte_threshold: usize,
+}
+
+impl std::fmt::Debug for RoomStorageConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RoomStorageConfig")
+            .field(
+                "compaction_entry_threshold",
+                &self.compaction_entry_threshold,
+            )
+            .field("compaction_byte_threshold", &self.compaction_byte_threshold)
+            .finish()
+    }
+}